@@ -2,7 +2,9 @@
 
 use std::fmt::{self, Debug, Display, Formatter};
 
-use kvproto::kvrpcpb::{CommandPri, Context, GetRequest, RawGetRequest};
+use kvproto::kvrpcpb::{
+    BatchGetRequest, CommandPri, Context, GetRequest, RawBatchGetRequest, RawGetRequest,
+};
 use tikv_util::collections::HashMap;
 use txn_types::{Key, Lock, Mutation, TimeStamp};
 
@@ -43,6 +45,116 @@ impl PointGetCommand {
     }
 }
 
+/// Get many values sharing one snapshot, coalescing what would otherwise be many independent
+/// [`PointGetCommand`](PointGetCommand)s into a single scheduled command.
+///
+/// TiDB's BatchGet/RawBatchGet plans issue many single-key reads together; servicing each as its
+/// own command wastes a snapshot acquisition and a scheduling round per key. A
+/// `BatchPointGetCommand` acquires one snapshot for `keys` and resolves them together, with
+/// per-key lock/value results returned in the same order as `keys`.
+pub struct BatchPointGetCommand {
+    pub ctx: Context,
+    pub keys: Vec<Key>,
+    /// None if this is a raw batch get, Some if this is a transactional batch get.
+    pub ts: Option<TimeStamp>,
+}
+
+impl BatchPointGetCommand {
+    pub fn from_batch_get(request: &mut BatchGetRequest) -> Self {
+        BatchPointGetCommand {
+            ctx: request.take_context(),
+            keys: request
+                .get_keys()
+                .iter()
+                .map(|k| Key::from_raw(k))
+                .collect(),
+            ts: Some(request.get_version().into()),
+        }
+    }
+
+    pub fn from_raw_batch_get(request: &mut RawBatchGetRequest) -> Self {
+        BatchPointGetCommand {
+            ctx: request.take_context(),
+            keys: request
+                .get_keys()
+                .iter()
+                .map(|k| Key::from_raw(k))
+                .collect(),
+            ts: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn from_keys_ts(keys: Vec<Key>, ts: Option<TimeStamp>) -> Self {
+        BatchPointGetCommand {
+            ctx: Context::default(),
+            keys,
+            ts,
+        }
+    }
+
+    /// Splits this batch back into single-key `PointGetCommand`s, preserving order.
+    ///
+    /// The single-key case is special-cased to move `ctx` instead of cloning it, since a batch
+    /// of one is the common shape produced by coalescing a `BatchGet` down to its per-key reads.
+    pub fn into_single_commands(self) -> Vec<PointGetCommand> {
+        let ts = self.ts;
+        let mut keys = self.keys.into_iter();
+        if keys.len() == 1 {
+            return vec![PointGetCommand {
+                ctx: self.ctx,
+                key: keys.next().unwrap(),
+                ts,
+            }];
+        }
+        let ctx = self.ctx;
+        keys.map(|key| PointGetCommand {
+            ctx: ctx.clone(),
+            key,
+            ts,
+        })
+        .collect()
+    }
+}
+
+/// The existence assertion requested for a single mutation in a [`Prewrite`](CommandKind::Prewrite).
+///
+/// Assertions let the SQL layer push uniqueness and lost-lock checks down into prewrite instead
+/// of doing extra reads: `NotExist` enforces INSERT uniqueness, `Exist` requires a prior
+/// committed write (e.g. for UPDATE), and `DoPessimisticCheck` requires that a pessimistic lock
+/// for this txn is already present on the key, to catch a lost lock in a pessimistic
+/// transaction. All three report failure the same way, through [`AssertionFailure`](AssertionFailure).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assertion {
+    /// No assertion is made about this key.
+    None,
+    /// The key must have no committed write visible at `start_ts`.
+    NotExist,
+    /// The key must have a committed write visible at `start_ts`.
+    Exist,
+    /// A pessimistic lock belonging to this transaction must already be present on the key.
+    DoPessimisticCheck,
+}
+
+impl Default for Assertion {
+    fn default() -> Assertion {
+        Assertion::None
+    }
+}
+
+/// Returned from `Prewrite` when a requested [`Assertion`](Assertion) does not hold.
+#[derive(Debug)]
+pub struct AssertionFailure {
+    pub key: Key,
+    pub assertion: Assertion,
+    /// For `Exist`/`NotExist`, the start_ts of the existing committed write found for `key`, or
+    /// zero if none exists. For `DoPessimisticCheck`, zero: no pessimistic lock was found.
+    pub existing_start_ts: TimeStamp,
+    /// For `Exist`/`NotExist`, the commit_ts of the existing committed write found for `key`, or
+    /// zero if none exists. For `DoPessimisticCheck`, zero: no pessimistic lock was found.
+    pub existing_commit_ts: TimeStamp,
+}
+
 /// Store Transaction scheduler commands.
 ///
 /// Learn more about our transaction system at
@@ -63,9 +175,21 @@ pub enum CommandKind {
     /// or a [`Rollback`](CommandKind::Rollback) should follow.
     ///
     /// If `options.for_update_ts` is `0`, the transaction is optimistic. Else it is pessimistic.
+    ///
+    /// If `options.try_one_pc` is set, and the transaction fits in a single region with no
+    /// conflicting writes and a viable commit_ts in `options.min_commit_ts..=options.max_commit_ts`,
+    /// the write path commits the mutations directly instead of writing locks, collapsing
+    /// `Prewrite` and [`Commit`](CommandKind::Commit) into one round trip (1PC). The chosen
+    /// commit_ts is returned to the caller. If the 1PC constraints cannot be met, the command
+    /// falls back to writing ordinary locks, exactly as if `try_one_pc` had not been set.
     Prewrite {
-        /// The set of mutations to apply.
-        mutations: Vec<Mutation>,
+        /// The set of mutations to apply, each paired with the existence assertion to check for
+        /// it (`Assertion::None` if none is requested). Pairing keeps the assertion in lock-step
+        /// with its mutation by construction, the same way `AcquirePessimisticLock::keys` pairs
+        /// each key with its own flag instead of using a second parallel `Vec`. See
+        /// [`Assertion`](Assertion) for the checks this enables, including `DoPessimisticCheck`,
+        /// which replaces the old mutation-indexed `Options::is_pessimistic_lock` flag.
+        mutations: Vec<(Mutation, Assertion)>,
         /// The primary lock. Secondary locks (from `mutations`) will refer to the primary lock.
         primary: Vec<u8>,
         /// The transaction timestamp.
@@ -75,6 +199,11 @@ pub enum CommandKind {
     /// Acquire a Pessimistic lock on the keys.
     ///
     /// This can be rolled back with a [`PessimisticRollback`](CommandKind::PessimisticRollback) command.
+    ///
+    /// If acquiring the lock would block (subject to `options.wait_timeout`), the scheduler
+    /// registers a wait-for edge in the [`deadlock`](super::deadlock) detector. A cycle found
+    /// there aborts the youngest waiter with a deadlock error instead of waiting out the
+    /// timeout; the edge is removed again once the lock is granted, rolled back, or times out.
     AcquirePessimisticLock {
         /// The set of keys to lock.
         keys: Vec<(Key, bool)>,
@@ -82,6 +211,10 @@ pub enum CommandKind {
         primary: Vec<u8>,
         /// The transaction timestamp.
         start_ts: TimeStamp,
+        /// A monotonically increasing identifier assigned to this transaction, used to key
+        /// wait-for edges in the [`deadlock`](super::deadlock) detector independently of
+        /// `start_ts`, which may change when the transaction is retried.
+        txn_id: u64,
         options: Options,
     },
     /// Commit the transaction that started at `lock_ts`.
@@ -143,6 +276,10 @@ pub enum CommandKind {
     /// lock. If the primary lock exists but is not expired, it may update the transaction's
     /// `min_commit_ts`. Returns a [`TxnStatus`](TxnStatus) to represent the status.
     ///
+    /// Expiry is decided by comparing `current_ts` against the lock's `Options::expire_ts` when
+    /// it was set at lock creation time, falling back to `lock_ttl` plus the lock's creation ts
+    /// otherwise, so expiry is deterministic regardless of clock skew between requests.
+    ///
     /// This is invoked on a transaction's primary lock. The lock may be generated by either
     /// [`AcquirePessimisticLock`](CommandKind::AcquirePessimisticLock) or
     /// [`Prewrite`](CommandKind::Prewrite).
@@ -267,7 +404,13 @@ impl Command {
 
     pub fn tag(&self) -> metrics::CommandKind {
         match self.kind {
-            CommandKind::Prewrite { .. } => metrics::CommandKind::prewrite,
+            CommandKind::Prewrite { ref options, .. } => {
+                if options.try_one_pc {
+                    metrics::CommandKind::one_pc_prewrite
+                } else {
+                    metrics::CommandKind::prewrite
+                }
+            }
             CommandKind::AcquirePessimisticLock { .. } => {
                 metrics::CommandKind::acquire_pessimistic_lock
             }
@@ -312,7 +455,7 @@ impl Command {
         let mut bytes = 0;
         match self.kind {
             CommandKind::Prewrite { ref mutations, .. } => {
-                for m in mutations {
+                for (m, _) in mutations {
                     match *m {
                         Mutation::Put((ref key, ref value))
                         | Mutation::Insert((ref key, ref value)) => {
@@ -375,24 +518,28 @@ impl Display for Command {
             CommandKind::Prewrite {
                 ref mutations,
                 start_ts,
+                ref options,
                 ..
             } => write!(
                 f,
-                "kv::command::prewrite mutations({}) @ {} | {:?}",
+                "kv::command::prewrite mutations({}) @ {} 1pc({}) | {:?}",
                 mutations.len(),
                 start_ts,
+                options.try_one_pc,
                 self.ctx,
             ),
             CommandKind::AcquirePessimisticLock {
                 ref keys,
                 start_ts,
+                txn_id,
                 ref options,
                 ..
             } => write!(
                 f,
-                "kv::command::acquirepessimisticlock keys({}) @ {} {} | {:?}",
+                "kv::command::acquirepessimisticlock keys({}) @ {} txn({}) {} | {:?}",
                 keys.len(),
                 start_ts,
+                txn_id,
                 options.for_update_ts,
                 self.ctx,
             ),
@@ -518,13 +665,26 @@ pub struct Options {
     pub reverse_scan: bool,
     pub is_first_lock: bool,
     pub for_update_ts: TimeStamp,
-    pub is_pessimistic_lock: Vec<bool>,
     // How many keys this transaction involved.
     pub txn_size: u64,
     pub min_commit_ts: TimeStamp,
     // Time to wait for lock released in milliseconds when encountering locks.
     // 0 means using default timeout. Negative means no wait.
     pub wait_timeout: i64,
+    /// Try to commit in one phase (1PC) instead of writing locks, provided the whole
+    /// transaction fits in a single region and a commit_ts can be found in
+    /// `min_commit_ts..=max_commit_ts`. Falls back to normal 2PC if those constraints fail.
+    pub try_one_pc: bool,
+    /// The upper bound of the commit_ts that the 1PC fast path may choose. Only consulted
+    /// when `try_one_pc` is set; `min_commit_ts` already provides the lower bound.
+    pub max_commit_ts: TimeStamp,
+    /// An absolute expiration timestamp for locks created by this command, in addition to the
+    /// relative `lock_ttl`. When set, [`CheckTxnStatus`](CommandKind::CheckTxnStatus) compares
+    /// its `current_ts` directly against this value to decide expiry, rather than recomputing
+    /// it from `lock_ttl` plus the lock's creation time. This gives the GC/resolve path and
+    /// conflicting readers a single authoritative expiry value and avoids clock-skew ambiguity
+    /// between TTL and wall-clock checks. `None` falls back to the TTL-based computation.
+    pub expire_ts: Option<TimeStamp>,
 }
 
 impl Options {